@@ -3,14 +3,15 @@
 // For the full copyright and license information, please view the LICENSE
 // file that was distributed with this source code.
 
-// spell-checker:ignore (ToDO) tempdir dyld dylib optgrps libstdbuf
+// spell-checker:ignore (ToDO) tempdir dyld dylib optgrps libstdbuf geteuid
 
 use clap::{Arg, ArgAction, ArgMatches, Command};
 use std::os::unix::process::ExitStatusExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
-use tempfile::TempDir;
+#[cfg(not(feature = "feat_external_libstdbuf"))]
 use tempfile::tempdir;
+use tempfile::TempDir;
 use uucore::error::{FromIo, UClapError, UResult, USimpleError, UUsageError};
 use uucore::format_usage;
 use uucore::parser::parse_size::parse_size_u64;
@@ -34,7 +35,10 @@ mod options {
         target_os = "android",
         target_os = "freebsd",
         target_os = "netbsd",
-        target_os = "dragonfly"
+        target_os = "dragonfly",
+        target_os = "openbsd",
+        target_os = "solaris",
+        target_os = "illumos"
     )
 ))]
 const STDBUF_INJECT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libstdbuf.so"));
@@ -68,20 +72,40 @@ impl TryFrom<&ArgMatches> for ProgramOptions {
 
 struct ProgramOptionsError(String);
 
+/// The injection variable to set on the child `Command`, the extension of the
+/// library file it points at, and any extra environment variables that are
+/// required for the injection to actually take effect on that platform.
+type PreloadStrings = (
+    &'static str,
+    &'static str,
+    &'static [(&'static str, &'static str)],
+);
+
 #[cfg(any(
     target_os = "linux",
     target_os = "android",
     target_os = "freebsd",
     target_os = "netbsd",
-    target_os = "dragonfly"
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "solaris",
+    target_os = "illumos"
 ))]
-fn preload_strings() -> UResult<(&'static str, &'static str)> {
-    Ok(("LD_PRELOAD", "so"))
+fn preload_strings() -> UResult<PreloadStrings> {
+    Ok(("LD_PRELOAD", "so", &[]))
 }
 
+// `DYLD_LIBRARY_PATH` only extends dyld's library *search path*; it does not
+// force a library to load into a process that doesn't already link it.
+// `DYLD_INSERT_LIBRARIES` is the mechanism dyld actually uses for interposition,
+// but it requires `DYLD_FORCE_FLAT_NAMESPACE` so symbols resolve across images.
 #[cfg(target_vendor = "apple")]
-fn preload_strings() -> UResult<(&'static str, &'static str)> {
-    Ok(("DYLD_LIBRARY_PATH", "dylib"))
+fn preload_strings() -> UResult<PreloadStrings> {
+    Ok((
+        "DYLD_INSERT_LIBRARIES",
+        "dylib",
+        &[("DYLD_FORCE_FLAT_NAMESPACE", "1")],
+    ))
 }
 
 #[cfg(not(any(
@@ -90,9 +114,12 @@ fn preload_strings() -> UResult<(&'static str, &'static str)> {
     target_os = "freebsd",
     target_os = "netbsd",
     target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "solaris",
+    target_os = "illumos",
     target_vendor = "apple"
 )))]
-fn preload_strings() -> UResult<(&'static str, &'static str)> {
+fn preload_strings() -> UResult<PreloadStrings> {
     Err(USimpleError::new(
         1,
         "Command not supported for this operating system!",
@@ -138,23 +165,181 @@ fn set_command_env(command: &mut process::Command, buffer_name: &str, buffer_typ
     }
 }
 
+/// Directory the extracted injection library is cached under, so repeated
+/// `stdbuf` invocations (e.g. in a tight shell loop) don't rewrite it to disk
+/// every time. Honors `XDG_CACHE_HOME`, falling back to `$HOME/.cache`, and
+/// finally to the system temp dir if neither is set.
+#[cfg(not(feature = "feat_external_libstdbuf"))]
+fn cache_dir() -> PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("uutils-stdbuf")
+}
+
+/// Hash the embedded library bytes so a rebuilt `libstdbuf` (e.g. after an
+/// upgrade) naturally gets its own cache entry instead of reusing a stale one.
+#[cfg(not(feature = "feat_external_libstdbuf"))]
+fn content_hash(bytes: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(all(unix, not(feature = "feat_external_libstdbuf")))]
+fn restrict_to_owner(file: &std::fs::File) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(all(unix, not(feature = "feat_external_libstdbuf")))]
+fn current_uid() -> libc::uid_t {
+    unsafe { libc::geteuid() }
+}
+
+/// Only ever trust a cache directory we own exclusively: not a symlink, owned
+/// by us, and not writable by any other user, so a different local account
+/// can't plant a directory (or a file in it) for us to pick up. Creates it
+/// owner-only (`0o700`) if it doesn't exist yet.
+#[cfg(all(unix, not(feature = "feat_external_libstdbuf")))]
+fn ensure_safe_cache_dir(dir: &Path) -> Option<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match std::fs::symlink_metadata(dir) {
+        Ok(metadata) if metadata.is_dir() && metadata.uid() as libc::uid_t == current_uid() => {
+            // Reject if writable by group or other (mode bits 0o020 / 0o002).
+            (metadata.mode() & 0o022 == 0).then_some(())
+        }
+        Ok(_) => None,
+        Err(_) => {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .ok()?;
+            ensure_safe_cache_dir(dir)
+        }
+    }
+}
+
+/// Verify a pre-existing cache entry really is our own, byte-for-byte
+/// unmodified `STDBUF_INJECT` — not just a same-length file an attacker left
+/// behind to get their code `LD_PRELOAD`ed into a child process. Rejects
+/// symlinks so a pre-staged one can't be silently followed.
+#[cfg(not(feature = "feat_external_libstdbuf"))]
+fn cached_file_matches(path: &Path) -> bool {
+    use std::io::Read;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => {}
+        _ => return false,
+    }
+
+    let Ok(mut file) = std::fs::File::open(path) else {
+        return false;
+    };
+    let mut contents = Vec::with_capacity(STDBUF_INJECT.len());
+    if file.read_to_end(&mut contents).is_err() {
+        return false;
+    }
+    contents == STDBUF_INJECT
+}
+
+/// Extract `STDBUF_INJECT` into the cache directory, reusing an existing copy
+/// once its contents are verified to really be our own library. Returns
+/// `None` on any failure or safety concern (e.g. the cache directory is
+/// writable by another user), so the caller can fall back to extracting into
+/// a fresh temp directory instead.
+#[cfg(not(feature = "feat_external_libstdbuf"))]
+fn get_cached_inject_path(extension: &str) -> Option<PathBuf> {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    let dir = cache_dir();
+    ensure_safe_cache_dir(&dir)?;
+
+    let file_name = format!("libstdbuf-{:016x}", content_hash(STDBUF_INJECT));
+    let cached_path = dir.join(file_name).with_extension(extension);
+
+    if cached_file_matches(&cached_path) {
+        return Some(cached_path);
+    }
+
+    // Write to a sibling temp file, refusing to clobber anything already
+    // there (so a pre-staged symlink is never silently followed), then
+    // rename into place so concurrent `stdbuf` processes never observe a
+    // partially written cache file.
+    let tmp_path = dir.join(format!(
+        ".libstdbuf-{:016x}.tmp-{}",
+        content_hash(STDBUF_INJECT),
+        process::id()
+    ));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)
+        .ok()?;
+    restrict_to_owner(&file).ok()?;
+    file.write_all(STDBUF_INJECT).ok()?;
+    drop(file);
+    std::fs::rename(&tmp_path, &cached_path).ok()?;
+
+    Some(cached_path)
+}
+
+/// Returns the injection variable to set, the path to the library to inject,
+/// any extra env vars required on this platform, and (when the cache cannot
+/// be used) the `TempDir` the library was extracted into, which the caller
+/// must keep alive for as long as the child process needs it.
+type PreloadEnv = (
+    String,
+    PathBuf,
+    &'static [(&'static str, &'static str)],
+    Option<TempDir>,
+);
+
 #[cfg(not(feature = "feat_external_libstdbuf"))]
-fn get_preload_env(tmp_dir: &TempDir) -> UResult<(String, PathBuf)> {
+fn get_preload_env() -> UResult<PreloadEnv> {
     use std::fs::File;
     use std::io::Write;
 
-    let (preload, extension) = preload_strings()?;
-    let inject_path = tmp_dir.path().join("libstdbuf").with_extension(extension);
+    let (preload, extension, extra_env) = preload_strings()?;
 
-    let mut file = File::create(&inject_path)?;
+    if let Some(cached) = get_cached_inject_path(extension) {
+        return Ok((preload.to_owned(), cached, extra_env, None));
+    }
+
+    // No usable cache entry: fall back to extracting into a fresh temp
+    // directory for this invocation only.
+    let tmp_dir = tempdir().unwrap();
+    let path = tmp_dir.path().join("libstdbuf").with_extension(extension);
+    let mut file = File::create(&path)?;
     file.write_all(STDBUF_INJECT)?;
 
-    Ok((preload.to_owned(), inject_path))
+    Ok((preload.to_owned(), path, extra_env, Some(tmp_dir)))
+}
+
+/// Build the value to set for `preload_env`, joining it onto whatever the user
+/// already has set (e.g. `LD_PRELOAD=libfoo.so`) instead of clobbering it.
+fn build_preload_value(preload_env: &str, libstdbuf: &Path) -> std::ffi::OsString {
+    let mut value = std::ffi::OsString::new();
+    if let Some(existing) = std::env::var_os(preload_env) {
+        if !existing.is_empty() {
+            value.push(libstdbuf);
+            value.push(":");
+            value.push(existing);
+            return value;
+        }
+    }
+    value.push(libstdbuf);
+    value
 }
 
 #[cfg(feature = "feat_external_libstdbuf")]
-fn get_preload_env(_tmp_dir: &TempDir) -> UResult<(String, PathBuf)> {
-    let (preload, extension) = preload_strings()?;
+fn get_preload_env() -> UResult<PreloadEnv> {
+    let (preload, extension, extra_env) = preload_strings()?;
 
     // Use the directory provided at compile time via LIBSTDBUF_DIR environment variable
     // This will fail to compile if LIBSTDBUF_DIR is not set, which is the desired behavior
@@ -163,7 +348,7 @@ fn get_preload_env(_tmp_dir: &TempDir) -> UResult<(String, PathBuf)> {
         .join("libstdbuf")
         .with_extension(extension);
     if path_buf.exists() {
-        return Ok((preload.to_owned(), path_buf));
+        return Ok((preload.to_owned(), path_buf, extra_env, None));
     }
 
     Err(USimpleError::new(
@@ -185,9 +370,13 @@ pub fn uumain(args: impl uucore::Args) -> UResult<()> {
     let mut command = process::Command::new(command_values.next().unwrap());
     let command_params: Vec<&str> = command_values.map(|s| s.as_ref()).collect();
 
-    let tmp_dir = tempdir().unwrap();
-    let (preload_env, libstdbuf) = get_preload_env(&tmp_dir)?;
-    command.env(preload_env, libstdbuf);
+    // `_tmp_dir` is kept alive (if present) until the child process has
+    // finished using `libstdbuf`, but only actually gets created inside
+    // `get_preload_env` when the cache cannot be used.
+    let (preload_env, libstdbuf, extra_env, _tmp_dir) = get_preload_env()?;
+    let preload_value = build_preload_value(&preload_env, &libstdbuf);
+    command.env(preload_env, preload_value);
+    command.envs(extra_env.iter().copied());
     set_command_env(&mut command, "_STDBUF_I", &options.stdin);
     set_command_env(&mut command, "_STDBUF_O", &options.stdout);
     set_command_env(&mut command, "_STDBUF_E", &options.stderr);
@@ -267,3 +456,90 @@ pub fn uu_app() -> Command {
                 .value_hint(clap::ValueHint::CommandName),
         )
 }
+#[cfg(all(test, unix, not(feature = "feat_external_libstdbuf")))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn build_preload_value_appends_to_existing() {
+        let var = "STDBUF_TEST_PRELOAD_APPEND";
+        unsafe { std::env::set_var(var, "existing.so") };
+        let value = build_preload_value(var, Path::new("/tmp/injected.so"));
+        unsafe { std::env::remove_var(var) };
+        assert_eq!(value, "/tmp/injected.so:existing.so");
+    }
+
+    #[test]
+    fn build_preload_value_without_existing() {
+        let var = "STDBUF_TEST_PRELOAD_EMPTY";
+        unsafe { std::env::remove_var(var) };
+        let value = build_preload_value(var, Path::new("/tmp/injected.so"));
+        assert_eq!(value, "/tmp/injected.so");
+    }
+
+    #[test]
+    fn content_hash_is_deterministic_and_sensitive_to_bytes() {
+        assert_eq!(content_hash(b"abc"), content_hash(b"abc"));
+        assert_ne!(content_hash(b"abc"), content_hash(b"abd"));
+    }
+
+    #[test]
+    fn cached_file_matches_accepts_correct_contents() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("libstdbuf.so");
+        std::fs::write(&path, STDBUF_INJECT).unwrap();
+        assert!(cached_file_matches(&path));
+    }
+
+    #[test]
+    fn cached_file_matches_rejects_tampered_contents() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("libstdbuf.so");
+        std::fs::write(&path, b"not the real library").unwrap();
+        assert!(!cached_file_matches(&path));
+    }
+
+    #[test]
+    fn cached_file_matches_rejects_missing_file() {
+        let dir = tempdir().unwrap();
+        assert!(!cached_file_matches(&dir.path().join("missing.so")));
+    }
+
+    #[test]
+    fn ensure_safe_cache_dir_creates_owner_only_dir() {
+        let parent = tempdir().unwrap();
+        let cache = parent.path().join("cache");
+        assert!(ensure_safe_cache_dir(&cache).is_some());
+        let mode = std::fs::metadata(&cache).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o700);
+    }
+
+    #[test]
+    fn ensure_safe_cache_dir_rejects_world_writable_dir() {
+        let parent = tempdir().unwrap();
+        let cache = parent.path().join("cache");
+        std::fs::create_dir(&cache).unwrap();
+        std::fs::set_permissions(&cache, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(ensure_safe_cache_dir(&cache).is_none());
+    }
+
+    #[test]
+    fn get_cached_inject_path_reuses_and_recovers_from_tampering() {
+        let cache_home = tempdir().unwrap();
+        unsafe { std::env::set_var("XDG_CACHE_HOME", cache_home.path()) };
+
+        let first = get_cached_inject_path("so").expect("first extraction should succeed");
+        assert_eq!(std::fs::read(&first).unwrap(), STDBUF_INJECT);
+
+        let second = get_cached_inject_path("so").expect("second call should reuse the cache");
+        assert_eq!(first, second);
+
+        std::fs::write(&second, b"tampered").unwrap();
+        let third = get_cached_inject_path("so").expect("tampered entry should be rewritten");
+        assert_eq!(std::fs::read(&third).unwrap(), STDBUF_INJECT);
+
+        unsafe { std::env::remove_var("XDG_CACHE_HOME") };
+    }
+}